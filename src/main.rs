@@ -1,17 +1,19 @@
 use std::{
-    collections::{btree_set::Iter, BTreeSet},
+    collections::{btree_set::Iter, BTreeMap, BTreeSet},
     fs::File,
     io::{BufRead, Read},
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
 };
 
 use cfg_rs::*;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 #[cfg(target_env = "musl")]
 #[global_allocator]
 //static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[derive(FromConfig, Debug)]
+#[derive(FromConfig, Debug, Default)]
 pub struct Config {
     #[config(default = false)]
     reverse: bool,
@@ -20,53 +22,179 @@ pub struct Config {
     exclude: Option<String>,
     prefix_v4: Option<u8>,
     prefix_v6: Option<u8>,
+    /// `-m`/`--max-length`: refuse to aggregate a prefix shorter than this.
+    max_length: Option<u8>,
+    /// `-4`: restrict processing to IPv4 input/output.
+    #[config(default = false)]
+    ipv4_only: bool,
+    /// `-6`: restrict processing to IPv6 input/output.
+    #[config(default = false)]
+    ipv6_only: bool,
+    /// `-t`/`--truncate`: reject host-bit-set prefixes instead of masking them.
+    #[config(default = false)]
+    truncate: bool,
+    /// `--strict`: fail parsing on non-canonical (host-bit-set) prefixes.
+    #[config(default = false)]
+    strict: bool,
+    /// `--format`: output as `cidr` (default), `json`, or `range`.
+    format: Option<String>,
+    /// `--annotate`: append ` # <label>` comments using each subnet's source label.
+    #[config(default = false)]
+    annotate: bool,
+    /// `--lookup=<addr>`: print the most specific prefix covering `addr`
+    /// (a longest-prefix-match query) instead of aggregating. This runs
+    /// against the already-`shrink`-ed set, so it reports the aggregated
+    /// covering block rather than the original, more specific input prefix
+    /// it was folded into.
+    lookup: Option<String>,
+    /// `--set-op=union|intersection|difference`: combine the input with the
+    /// list read from `--with` instead of aggregating it alone.
+    set_op: Option<String>,
+    /// `--with=<file>`: the second operand list for `--set-op`.
+    with: Option<String>,
 }
 
 fn main() -> Result<(), ConfigError> {
-    let config = init_args(Configuration::with_predefined_builder()).init()?;
+    let (builder, files) = init_args(Configuration::with_predefined_builder());
+    let config = builder.init()?;
     let conf: Config = config.get("")?;
     let mut list = SubnetList::default();
     // println!("{:?}", conf);
-    list.read_stdin(conf.reverse, conf.exclude, conf.merge)?;
-    for subnet in list.iter() {
-        match subnet.net {
-            Ok(net) => {
-                if let Some(prefix) = conf.prefix_v4 {
-                    if prefix <= 32 && prefix > subnet.mask {
-                        let count: u32 = 1 << (prefix - subnet.mask);
-                        for i in 0..count {
-                            let sub = Subnet::new_v4(net + (i << (32 - prefix)), prefix);
-                            println!("{}", sub.to_string());
-                        }
-                        continue;
-                    }
+    list.read_stdin(&conf, &files)?;
+
+    if let Some(addr) = &conf.lookup {
+        let ip: IpAddr = addr
+            .parse()
+            .map_err(|_| ConfigError::RefValueRecursiveError)?;
+        match list.lpm(ip) {
+            Some(subnet) => println!("{}", subnet.to_string()),
+            None => println!("no match"),
+        }
+        return Ok(());
+    }
+
+    if let Some(op) = &conf.set_op {
+        let path = conf
+            .with
+            .as_ref()
+            .ok_or(ConfigError::RefValueRecursiveError)?;
+        let mut other = SubnetList {
+            max_length: conf.max_length,
+            ..SubnetList::default()
+        };
+        let mut file = File::open(path)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        for line in buf.lines() {
+            other.parse_line(line, None, &conf);
+        }
+        other.shrink(true);
+        list = match op.as_str() {
+            "union" => list.union(&other),
+            "intersection" => list.intersection(&other),
+            "difference" => list.difference(&other),
+            _ => return Err(ConfigError::RefValueRecursiveError),
+        };
+    }
+
+    match conf.format.as_deref() {
+        Some("json") => {
+            let subnets: Vec<&Subnet> = list
+                .iter()
+                .filter(|s| !(conf.ipv4_only && s.net.is_err()) && !(conf.ipv6_only && s.net.is_ok()))
+                .collect();
+            println!(
+                "{}",
+                serde_json::to_string(&subnets).expect("SubnetList serialization cannot fail")
+            );
+        }
+        Some("range") => {
+            for subnet in list.iter() {
+                if conf.ipv4_only && subnet.net.is_err() {
+                    continue;
                 }
+                if conf.ipv6_only && subnet.net.is_ok() {
+                    continue;
+                }
+                let (first, last) = subnet.range();
+                println!("{},{}", first, last);
             }
-            Err(net) => {
-                if let Some(prefix) = conf.prefix_v6 {
-                    if prefix <= 128 && prefix > subnet.mask {
-                        let count: u128 = 1 << (prefix - subnet.mask);
-                        for i in 0..count {
-                            let sub = Subnet::new_v6(net + (i << (128 - prefix)), prefix);
-                            println!("{}", sub.to_string());
+        }
+        _ => {
+            for subnet in list.iter() {
+                if conf.ipv4_only && subnet.net.is_err() {
+                    continue;
+                }
+                if conf.ipv6_only && subnet.net.is_ok() {
+                    continue;
+                }
+                match subnet.net {
+                    Ok(net) => {
+                        if let Some(prefix) = conf.prefix_v4 {
+                            if prefix <= 32 && prefix > subnet.mask {
+                                let count: u32 = 1 << (prefix - subnet.mask);
+                                for i in 0..count {
+                                    let sub = Subnet::new_v4(net + (i << (32 - prefix)), prefix);
+                                    println!("{}", sub.to_string());
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    Err(net) => {
+                        if let Some(prefix) = conf.prefix_v6 {
+                            if prefix <= 128 && prefix > subnet.mask {
+                                let count: u128 = 1 << (prefix - subnet.mask);
+                                for i in 0..count {
+                                    let sub = Subnet::new_v6(net + (i << (128 - prefix)), prefix);
+                                    println!("{}", sub.to_string());
+                                }
+                                continue;
+                            }
                         }
+                    }
+                }
+                if conf.annotate {
+                    if let Some(label) = list.label_of(subnet) {
+                        println!("{} # {}", subnet.to_string(), label);
                         continue;
                     }
                 }
+                println!("{}", subnet.to_string());
             }
         }
-        println!("{}", subnet.to_string());
     }
     Ok(())
 }
 
-fn init_args(mut builder: PredefinedConfigurationBuilder) -> PredefinedConfigurationBuilder {
-    for arg in std::env::args() {
-        if arg.find("--") == Some(0) {
-            builder = add_arg(&arg["--".len()..], builder);
+/// Parses argv into a config builder plus the positional input file paths,
+/// aggregate6-style: `--key=value`/`--flag` long options, `-4`/`-6`/`-t`
+/// short flags, `-m`/`--max-length <n>` with a separate value; other `-x`
+/// short flags are ignored, and everything else is treated as an input file
+/// (read from stdin when none are given).
+fn init_args(
+    mut builder: PredefinedConfigurationBuilder,
+) -> (PredefinedConfigurationBuilder, Vec<String>) {
+    let mut files = vec![];
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "-m" || arg == "--max-length" {
+            i += 1;
+            if let Some(val) = args.get(i) {
+                builder = builder.set("max_length", val.clone());
+            }
+        } else if let Some(rest) = arg.strip_prefix("--") {
+            builder = add_arg(rest, builder);
+        } else if let Some(rest) = arg.strip_prefix('-') {
+            builder = add_short_arg(rest, builder);
+        } else {
+            files.push(arg.clone());
         }
+        i += 1;
     }
-    builder
+    (builder, files)
 }
 
 fn add_arg(
@@ -84,15 +212,33 @@ fn add_arg(
                 val.to_owned()
             },
         );
+    } else {
+        builder = builder.set(arg.replace('-', "_"), "true".to_string());
     }
     builder
 }
 
+fn add_short_arg(
+    arg: &str,
+    builder: PredefinedConfigurationBuilder,
+) -> PredefinedConfigurationBuilder {
+    let key = match arg {
+        "4" => "ipv4_only",
+        "6" => "ipv6_only",
+        "t" => "truncate",
+        _ => return builder,
+    };
+    builder.set(key, "true".to_string())
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 struct Subnet {
     net: Result<u32, u128>,
     mask: u8,
     tag: u8,
+    /// Id of the input source this subnet came from, looked up in
+    /// `SubnetList::labels`. `0` means "no source label".
+    source: u8,
 }
 
 macro_rules! common_parse_fn {
@@ -102,6 +248,7 @@ macro_rules! common_parse_fn {
             mut from: $tp,
             mut to: $tp,
             tag: Option<u8>,
+            source: Option<u8>,
         ) -> Result<(), ConfigError> {
             let mut mask = $mp;
             while from <= to {
@@ -113,6 +260,9 @@ macro_rules! common_parse_fn {
                     if let Some(t) = tag {
                         item.tag = t;
                     }
+                    if let Some(s) = source {
+                        item.source = s;
+                    }
                     v.insert(item);
                     break;
                 }
@@ -121,6 +271,9 @@ macro_rules! common_parse_fn {
                     if let Some(t) = tag {
                         item.tag = t;
                     }
+                    if let Some(s) = source {
+                        item.source = s;
+                    }
                     v.insert(item);
                     from += 1;
                 }
@@ -129,6 +282,9 @@ macro_rules! common_parse_fn {
                     if let Some(t) = tag {
                         item.tag = t;
                     }
+                    if let Some(s) = source {
+                        item.source = s;
+                    }
                     v.insert(item);
                     to -= 1;
                 }
@@ -141,10 +297,21 @@ macro_rules! common_parse_fn {
             Ok(())
         }
 
-        fn $try_ip(s: &str, mask: Option<u8>) -> Result<Option<Self>, ConfigError> {
+        fn $try_ip(s: &str, mask: Option<u8>, conf: &Config) -> Result<Option<Self>, ConfigError> {
             let mask = mask.unwrap_or($mp);
             let addr: $tpa = s.parse()?;
             let net: $tp = $tp::from(addr);
+            let item = Self::$new(net, mask);
+            if item.is_valid() {
+                return Ok(Some(item));
+            }
+            if conf.strict {
+                return Err(ConfigError::RefValueRecursiveError);
+            }
+            if conf.truncate {
+                eprintln!("error: {}/{} has non-zero host bits, skipping", s, mask);
+                return Ok(None);
+            }
             let mk = $mp - mask;
             Ok(Some(Self::$new((net >> mk) << mk, mask)))
         }
@@ -156,6 +323,7 @@ impl Subnet {
             net: Ok(net),
             mask,
             tag: 0,
+            source: 0,
         }
     }
     fn new_v6(net: u128, mask: u8) -> Self {
@@ -163,10 +331,11 @@ impl Subnet {
             net: Err(net),
             mask,
             tag: 0,
+            source: 0,
         }
     }
 
-    fn parse_subnet(mut s: &str) -> Result<Option<Self>, ConfigError> {
+    fn parse_subnet(mut s: &str, conf: &Config) -> Result<Option<Self>, ConfigError> {
         s = Self::prepare_str(s);
         if s.is_empty() {
             return Ok(None);
@@ -176,23 +345,29 @@ impl Subnet {
             mask = Some((s[i + 1..]).parse()?);
             s = &s[0..i];
         }
-        Self::try_ipv4(s, mask).or_else(|_| Self::try_ipv6(s, mask))
+        Self::try_ipv4(s, mask, conf).or_else(|_| Self::try_ipv6(s, mask, conf))
     }
 
     common_parse_fn!(u32, Ipv4Addr, 32, parse_ipv4_range, new_v4, try_ipv4);
     common_parse_fn!(u128, Ipv6Addr, 128, parse_ipv6_range, new_v6, try_ipv6);
 
-    fn parse_range(s: &str, v: &mut SubnetList, tag: Option<u8>) -> Result<(), ConfigError> {
+    fn parse_range(
+        s: &str,
+        v: &mut SubnetList,
+        tag: Option<u8>,
+        source: Option<u8>,
+        conf: &Config,
+    ) -> Result<(), ConfigError> {
         let split: Vec<&str> = Self::prepare_str(s).split(',').take(2).collect();
-        let from = Subnet::parse_subnet(split[0])?
+        let from = Subnet::parse_subnet(split[0], conf)?
             .ok_or(ConfigError::RefValueRecursiveError)?
             .net;
-        let to = Subnet::parse_subnet(split[1])?
+        let to = Subnet::parse_subnet(split[1], conf)?
             .ok_or(ConfigError::RefValueRecursiveError)?
             .net;
         match (from, to) {
-            (Ok(from), Ok(to)) => Self::parse_ipv4_range(v, from, to, tag),
-            (Err(from), Err(to)) => Self::parse_ipv6_range(v, from, to, tag),
+            (Ok(from), Ok(to)) => Self::parse_ipv4_range(v, from, to, tag, source),
+            (Err(from), Err(to)) => Self::parse_ipv6_range(v, from, to, tag, source),
             _ => panic!("Error"),
         }
     }
@@ -205,14 +380,23 @@ impl Subnet {
         s
     }
 
-    fn parse(s: &str, vec: &mut SubnetList, tag: Option<u8>) -> Result<(), ConfigError> {
+    fn parse(
+        s: &str,
+        vec: &mut SubnetList,
+        tag: Option<u8>,
+        source: Option<u8>,
+        conf: &Config,
+    ) -> Result<(), ConfigError> {
         if s.contains(',') {
-            return Self::parse_range(s, vec, tag);
+            return Self::parse_range(s, vec, tag, source, conf);
         }
-        if let Some(mut x) = Self::parse_subnet(s)? {
+        if let Some(mut x) = Self::parse_subnet(s, conf)? {
             if let Some(t) = tag {
                 x.tag = t;
             }
+            if let Some(src) = source {
+                x.source = src;
+            }
             vec.insert(x);
         }
         Ok(())
@@ -256,6 +440,38 @@ impl Subnet {
             _ => false,
         }
     }
+
+    /// Whether the stored address already has all host bits zeroed for its
+    /// mask, i.e. it is the canonical network address for that prefix.
+    pub fn is_valid(&self) -> bool {
+        match self.net {
+            Ok(net) => self.mask >= 32 || net & (u32::MAX >> self.mask) == 0,
+            Err(net) => self.mask >= 128 || net & (u128::MAX >> self.mask) == 0,
+        }
+    }
+
+    /// The inverse of the `first,last` comma syntax accepted by `parse_range`:
+    /// the first and last address covered by this prefix.
+    pub fn range(&self) -> (String, String) {
+        match self.net {
+            Ok(net) => {
+                let last = if self.mask == 0 {
+                    u32::MAX
+                } else {
+                    net + (1u32 << (32 - self.mask)) - 1
+                };
+                (Ipv4Addr::from(net).to_string(), Ipv4Addr::from(last).to_string())
+            }
+            Err(net) => {
+                let last = if self.mask == 0 {
+                    u128::MAX
+                } else {
+                    net + (1u128 << (128 - self.mask)) - 1
+                };
+                (Ipv6Addr::from(net).to_string(), Ipv6Addr::from(last).to_string())
+            }
+        }
+    }
 }
 
 impl ToString for Subnet {
@@ -271,33 +487,129 @@ impl ToString for Subnet {
     }
 }
 
+impl Serialize for Subnet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Subnet::parse_subnet(&s, &Config::default())
+            .map_err(D::Error::custom)?
+            .ok_or_else(|| D::Error::custom("empty subnet"))
+    }
+}
+
+macro_rules! common_trie_fn {
+    ($node:ident, $tp:ident, $mp:literal) => {
+        #[derive(Default)]
+        struct $node {
+            subnet: Option<Subnet>,
+            children: [Option<Box<$node>>; 2],
+        }
+
+        impl $node {
+            fn insert(&mut self, net: $tp, mask: u8, subnet: Subnet) {
+                let mut node = self;
+                for i in 0..mask {
+                    let bit = ((net >> ($mp - 1 - i)) & 1) as usize;
+                    node = node.children[bit].get_or_insert_with(Default::default);
+                }
+                node.subnet = Some(subnet);
+            }
+
+            fn lookup(&self, net: $tp, bits: u8) -> Option<Subnet> {
+                let mut node = self;
+                let mut found = node.subnet;
+                for i in 0..bits {
+                    let bit = ((net >> ($mp - 1 - i)) & 1) as usize;
+                    match &node.children[bit] {
+                        Some(next) => node = next,
+                        None => break,
+                    }
+                    if node.subnet.is_some() {
+                        found = node.subnet;
+                    }
+                }
+                found
+            }
+        }
+    };
+}
+common_trie_fn!(TrieNodeV4, u32, 32);
+common_trie_fn!(TrieNodeV6, u128, 128);
+
 #[derive(Default)]
-struct SubnetList(BTreeSet<Subnet>);
+struct SubnetList {
+    set: BTreeSet<Subnet>,
+    trie_v4: TrieNodeV4,
+    trie_v6: TrieNodeV6,
+    max_length: Option<u8>,
+    /// Maps a `Subnet.source` id to the label it should be annotated with.
+    labels: BTreeMap<u8, String>,
+}
+
+impl Serialize for SubnetList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for SubnetList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut list = SubnetList::default();
+        for subnet in Vec::<Subnet>::deserialize(deserializer)? {
+            list.insert(subnet);
+        }
+        Ok(list)
+    }
+}
 
 impl SubnetList {
-    pub fn read_stdin(
-        &mut self,
-        reverse: bool,
-        exclude: Option<String>,
-        merge: bool,
-    ) -> Result<(), ConfigError> {
-        let stdin = std::io::stdin();
-        let lines = stdin.lock().lines();
-        for line in lines {
-            Subnet::parse(&line?, self, None).ok();
+    pub fn read_stdin(&mut self, conf: &Config, files: &[String]) -> Result<(), ConfigError> {
+        self.max_length = conf.max_length;
+        if files.is_empty() {
+            let stdin = std::io::stdin();
+            for line in stdin.lock().lines() {
+                self.parse_line(&line?, None, conf);
+            }
+        } else {
+            for (i, path) in files.iter().enumerate() {
+                // Each file is its own source, labelled by its file stem so
+                // `--annotate` can show where an aggregated line came from.
+                let source = if conf.annotate { (i as u8) + 1 } else { 0 };
+                if conf.annotate {
+                    let label = Path::new(path)
+                        .file_stem()
+                        .map(|s| s.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| path.clone());
+                    self.labels.insert(source, label);
+                }
+                let mut file = File::open(path)?;
+                let mut buf = String::new();
+                file.read_to_string(&mut buf)?;
+                for line in buf.lines() {
+                    self.parse_line(line, Some(source), conf);
+                }
+            }
         }
         self.shrink(true);
-        if let Some(file) = exclude {
+        if let Some(file) = &conf.exclude {
             let mut file = File::open(file)?;
             let mut buf = String::new();
             file.read_to_string(&mut buf)?;
-            let mut new = SubnetList::default();
+            let mut new = SubnetList {
+                max_length: conf.max_length,
+                ..SubnetList::default()
+            };
             for line in buf.lines() {
-                Subnet::parse(line, &mut new, None).ok();
+                new.parse_line(line, None, conf);
             }
             new.shrink(true);
-            self.merge(new, merge);
-        } else if reverse {
+            self.merge(new, conf.merge);
+        } else if conf.reverse {
             let ret = self.gap();
             let _ = std::mem::replace(self, ret);
         }
@@ -305,22 +617,50 @@ impl SubnetList {
         Ok(())
     }
 
+    /// Parses one input line, skipping it up front when `-4`/`-6` restricts
+    /// processing to the other address family.
+    fn parse_line(&mut self, line: &str, source: Option<u8>, conf: &Config) {
+        let s = Subnet::prepare_str(line);
+        if s.is_empty() {
+            return;
+        }
+        let is_v6 = s.contains(':');
+        if (conf.ipv4_only && is_v6) || (conf.ipv6_only && !is_v6) {
+            return;
+        }
+        if Subnet::parse(line, self, None, source, conf).is_err() {
+            eprintln!("error: {} is not a canonical prefix, skipping", line);
+        }
+    }
+
     fn merge(&mut self, new: SubnetList, merge: bool) {
-        for mut item in new.gap().0.into_iter() {
+        for mut item in new.gap().set.into_iter() {
             item.tag = 1;
             self.insert(item);
         }
         self.shrink(merge);
     }
 
+    /// The label a subnet should be annotated with, if any (see `--annotate`).
+    pub fn label_of(&self, subnet: &Subnet) -> Option<&str> {
+        self.labels.get(&subnet.source).map(String::as_str)
+    }
+
     pub fn insert(&mut self, subnet: Subnet) -> bool {
-        self.0.insert(subnet)
+        let inserted = self.set.insert(subnet);
+        if inserted {
+            match subnet.net {
+                Ok(net) => self.trie_v4.insert(net, subnet.mask, subnet),
+                Err(net) => self.trie_v6.insert(net, subnet.mask, subnet),
+            }
+        }
+        inserted
     }
 
     pub fn shrink(&mut self, merge: bool) {
         let mut vec: Vec<Subnet> = vec![];
         let mut last: Option<Subnet> = None;
-        for i in self.0.iter() {
+        for i in self.set.iter() {
             if let Some(l) = &mut last {
                 if l.contains(i) {
                     let len = vec.len();
@@ -329,9 +669,11 @@ impl SubnetList {
                 }
             }
             last = Some(*i);
-            merge_vec(&mut vec, *i);
+            merge_vec(&mut vec, *i, self.max_length);
         }
-        self.0.clear();
+        self.set.clear();
+        self.trie_v4 = TrieNodeV4::default();
+        self.trie_v6 = TrieNodeV6::default();
         for i in vec {
             if i.tag == 0 {
                 self.insert(i);
@@ -340,7 +682,24 @@ impl SubnetList {
     }
 
     pub fn iter(&self) -> Iter<Subnet> {
-        self.0.iter()
+        self.set.iter()
+    }
+
+    /// Returns the most specific subnet in this list that contains `addr`,
+    /// by walking the per-family radix trie bit-by-bit from the root.
+    pub fn lpm(&self, addr: IpAddr) -> Option<Subnet> {
+        match addr {
+            IpAddr::V4(a) => self.trie_v4.lookup(u32::from(a), 32),
+            IpAddr::V6(a) => self.trie_v6.lookup(u128::from(a), 128),
+        }
+    }
+
+    /// Returns whether some subnet already in this list covers `target`.
+    pub fn covers(&self, target: &Subnet) -> bool {
+        match target.net {
+            Ok(net) => self.trie_v4.lookup(net, target.mask).is_some(),
+            Err(net) => self.trie_v6.lookup(net, target.mask).is_some(),
+        }
     }
 
     pub fn gap(&self) -> Self {
@@ -351,7 +710,7 @@ impl SubnetList {
             match item.net {
                 Ok(s) => {
                     if s > last_v4.0 {
-                        let _ = Subnet::parse_ipv4_range(&mut list, last_v4.0, s - 1, None);
+                        let _ = Subnet::parse_ipv4_range(&mut list, last_v4.0, s - 1, None, None);
                     }
                     let x = s + (1 << (32 - item.mask));
                     if x > last_v4.0 {
@@ -360,7 +719,7 @@ impl SubnetList {
                 }
                 Err(s) => {
                     if s > last_v6.0 {
-                        let _ = Subnet::parse_ipv6_range(&mut list, last_v6.0, s - 1, None);
+                        let _ = Subnet::parse_ipv6_range(&mut list, last_v6.0, s - 1, None, None);
                     }
                     let x = s + (1 << (128 - item.mask));
                     if x > last_v6.0 {
@@ -370,18 +729,71 @@ impl SubnetList {
             }
         }
         if last_v4.0 < last_v4.1 {
-            let _ = Subnet::parse_ipv4_range(&mut list, last_v4.0, last_v4.1, None);
+            let _ = Subnet::parse_ipv4_range(&mut list, last_v4.0, last_v4.1, None, None);
         }
         if last_v6.0 < last_v6.1 {
-            let _ = Subnet::parse_ipv6_range(&mut list, last_v6.0, last_v6.1, None);
+            let _ = Subnet::parse_ipv6_range(&mut list, last_v6.0, last_v6.1, None, None);
+        }
+        list
+    }
+
+    /// Normalized union of `self` and `other`: every subnet from both, shrunk.
+    pub fn union(&self, other: &SubnetList) -> SubnetList {
+        let mut labels = self.labels.clone();
+        labels.extend(other.labels.clone());
+        let mut list = SubnetList {
+            labels,
+            max_length: self.max_length,
+            ..SubnetList::default()
+        };
+        for i in self.iter() {
+            list.insert(*i);
         }
+        for i in other.iter() {
+            list.insert(*i);
+        }
+        list.shrink(true);
         list
     }
+
+    /// Normalized intersection: for each subnet on either side, keep it when
+    /// the other list already covers it (the finer-masked prefix wins).
+    pub fn intersection(&self, other: &SubnetList) -> SubnetList {
+        let mut labels = self.labels.clone();
+        labels.extend(other.labels.clone());
+        let mut list = SubnetList {
+            labels,
+            max_length: self.max_length,
+            ..SubnetList::default()
+        };
+        for i in self.iter() {
+            if other.covers(i) {
+                list.insert(*i);
+            }
+        }
+        for i in other.iter() {
+            if self.covers(i) {
+                list.insert(*i);
+            }
+        }
+        list.shrink(true);
+        list
+    }
+
+    /// Normalized `self` minus `other`, i.e. `self` intersected with the
+    /// complement of `other`.
+    pub fn difference(&self, other: &SubnetList) -> SubnetList {
+        self.intersection(&other.gap())
+    }
 }
 
-fn merge_vec(vec: &mut Vec<Subnet>, mut i: Subnet) {
+fn merge_vec(vec: &mut Vec<Subnet>, mut i: Subnet, max_length: Option<u8>) {
     while let Some(mut l) = vec.pop() {
-        if l.tag == i.tag && l.is_next(&i) {
+        if l.tag == i.tag
+            && l.source == i.source
+            && l.is_next(&i)
+            && max_length.map_or(true, |cap| l.mask > cap)
+        {
             l.mask -= 1;
             i = l;
         } else {
@@ -397,17 +809,22 @@ mod tests {
 
     use cfg_rs::ConfigError;
 
-    use crate::{Subnet, SubnetList};
+    use crate::{Config, Subnet, SubnetList};
 
     macro_rules! assert_empty {
         ($source:expr) => {
-            assert_eq!(true, Subnet::parse_subnet($source)?.is_none());
+            assert_eq!(true, Subnet::parse_subnet($source, &Config::default())?.is_none());
         };
     }
 
     macro_rules! assert_subnet {
         ($source:expr => $target:expr) => {
-            assert_eq!($target, Subnet::parse_subnet($source)?.unwrap().to_string());
+            assert_eq!(
+                $target,
+                Subnet::parse_subnet($source, &Config::default())?
+                    .unwrap()
+                    .to_string()
+            );
         };
     }
 
@@ -425,9 +842,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn strict_test() -> Result<(), ConfigError> {
+        assert!(Subnet::parse_subnet("127.0.0.0/8", &Config::default())?
+            .unwrap()
+            .is_valid());
+        // The default config masks host bits while parsing, so build the
+        // non-canonical 127.0.0.1/8 directly.
+        assert!(!Subnet::new_v4(0x7f00_0001, 8).is_valid());
+
+        let strict = Config {
+            strict: true,
+            ..Config::default()
+        };
+        assert!(Subnet::parse_subnet("127.0.0.0/8", &strict).is_ok());
+        assert!(Subnet::parse_subnet("127.0.0.1/8", &strict).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn strict_parse_line_drops_invalid_test() {
+        let strict = Config {
+            strict: true,
+            ..Config::default()
+        };
+        let mut list = SubnetList::default();
+        list.parse_line("127.0.0.1/8", None, &strict);
+        assert_eq!(0, list.iter().count());
+
+        list.parse_line("127.0.0.0/8", None, &strict);
+        assert_eq!(1, list.iter().count());
+    }
+
     macro_rules! insert {
         ($set:ident.$x:expr) => {
-            if let Some(v) = Subnet::parse_subnet($x)? {
+            if let Some(v) = Subnet::parse_subnet($x, &Config::default())? {
                 $set.insert(v);
             }
         };
@@ -459,11 +908,19 @@ mod tests {
     #[test]
     fn range_test() -> Result<(), ConfigError> {
         let mut list = SubnetList::default();
-        Subnet::parse("223.255.229.0,223.255.230.255,", &mut list, None)?;
+        Subnet::parse(
+            "223.255.229.0,223.255.230.255,",
+            &mut list,
+            None,
+            None,
+            &Config::default(),
+        )?;
         Subnet::parse(
             "2c0f:fc00:b011::,2c0f:fc00:b011:ffff:ffff:ffff:ffff:ffff,",
             &mut list,
             None,
+            None,
+            &Config::default(),
         )?;
         for x in list.iter() {
             println!("{}", x.to_string());
@@ -506,4 +963,169 @@ mod tests {
         print_list(&list);
         Ok(())
     }
+
+    #[test]
+    fn max_length_stops_aggregation_test() -> Result<(), ConfigError> {
+        let mut list = SubnetList::default();
+        list.max_length = Some(24);
+        insert!(list. "1.1.0.0/24");
+        insert!(list. "1.1.1.0/24");
+        list.shrink(true);
+        assert_eq!(vec!["1.1.0.0/24", "1.1.1.0/24"], collect(&list));
+
+        let mut list = SubnetList::default();
+        list.max_length = Some(23);
+        insert!(list. "1.1.0.0/24");
+        insert!(list. "1.1.1.0/24");
+        list.shrink(true);
+        assert_eq!(vec!["1.1.0.0/23"], collect(&list));
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_skips_host_bits_test() {
+        let truncate = Config {
+            truncate: true,
+            ..Config::default()
+        };
+        let mut list = SubnetList::default();
+        list.parse_line("10.0.0.1/8", None, &truncate);
+        assert_eq!(0, list.iter().count());
+
+        list.parse_line("10.0.0.0/8", None, &truncate);
+        assert_eq!(1, list.iter().count());
+    }
+
+    #[test]
+    fn family_filter_test() {
+        let ipv4_only = Config {
+            ipv4_only: true,
+            ..Config::default()
+        };
+        let mut list = SubnetList::default();
+        list.parse_line("::1/128", None, &ipv4_only);
+        list.parse_line("10.0.0.0/8", None, &ipv4_only);
+        assert_eq!(vec!["10.0.0.0/8"], collect(&list));
+
+        let ipv6_only = Config {
+            ipv6_only: true,
+            ..Config::default()
+        };
+        let mut list = SubnetList::default();
+        list.parse_line("::1/128", None, &ipv6_only);
+        list.parse_line("10.0.0.0/8", None, &ipv6_only);
+        assert_eq!(vec!["::1/128"], collect(&list));
+    }
+
+    #[test]
+    fn test_lpm() -> Result<(), ConfigError> {
+        let mut list = SubnetList::default();
+        insert!(list. "10.0.0.0/8");
+        insert!(list. "10.1.0.0/16");
+        insert!(list. "10.1.1.0/24");
+        insert!(list. "192.168.0.0/16");
+
+        assert_eq!(
+            "10.1.1.0/24",
+            list.lpm("10.1.1.5".parse().unwrap())
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(
+            "10.1.0.0/16",
+            list.lpm("10.1.2.5".parse().unwrap())
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(
+            "10.0.0.0/8",
+            list.lpm("10.2.0.1".parse().unwrap()).unwrap().to_string()
+        );
+        assert!(list.lpm("172.16.0.1".parse().unwrap()).is_none());
+
+        assert!(list.covers(&Subnet::parse_subnet("10.1.1.128/25", &Config::default())?.unwrap()));
+        assert!(!list.covers(&Subnet::parse_subnet("172.16.0.0/16", &Config::default())?.unwrap()));
+        Ok(())
+    }
+
+    fn collect(list: &SubnetList) -> Vec<String> {
+        list.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set_ops() -> Result<(), ConfigError> {
+        let mut a = SubnetList::default();
+        insert!(a. "1.1.1.0/24");
+        insert!(a. "1.1.2.0/24");
+        a.shrink(true);
+
+        let mut b = SubnetList::default();
+        insert!(b. "1.1.1.128/25");
+        insert!(b. "1.1.3.0/24");
+        b.shrink(true);
+
+        assert_eq!(collect(&a.union(&b)), collect(&b.union(&a)));
+        assert_eq!(collect(&a.intersection(&b)), collect(&b.intersection(&a)));
+        assert_eq!(vec!["1.1.1.128/25".to_string()], collect(&a.intersection(&b)));
+
+        let diff = a.difference(&b);
+        let reconstructed = diff.union(&a.intersection(&b));
+        assert_eq!(collect(&a), collect(&reconstructed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_labels() -> Result<(), ConfigError> {
+        let mut list = SubnetList::default();
+        list.labels.insert(1, "allow".to_string());
+        list.labels.insert(2, "block".to_string());
+
+        // 1.1.0.0/24 and 1.1.1.0/24 are an even-boundary adjacency that
+        // `merge_vec` would otherwise combine into 1.1.0.0/23.
+        let mut a = Subnet::parse_subnet("1.1.0.0/24", &Config::default())?.unwrap();
+        a.source = 1;
+        let mut b = Subnet::parse_subnet("1.1.1.0/24", &Config::default())?.unwrap();
+        b.source = 2;
+        list.insert(a);
+        list.insert(b);
+
+        assert_eq!(Some("allow"), list.label_of(&a));
+        assert_eq!(Some("block"), list.label_of(&b));
+
+        // Adjacent prefixes from different sources must not be merged into
+        // one, since that would lose provenance.
+        list.shrink(true);
+        assert_eq!(2, list.iter().count());
+
+        // The same adjacency with a shared source still merges as usual.
+        let mut same = SubnetList::default();
+        let mut c = Subnet::parse_subnet("1.1.0.0/24", &Config::default())?.unwrap();
+        c.source = 1;
+        let mut d = Subnet::parse_subnet("1.1.1.0/24", &Config::default())?.unwrap();
+        d.source = 1;
+        same.insert(c);
+        same.insert(d);
+        same.shrink(true);
+        assert_eq!(1, same.iter().count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_and_range() -> Result<(), ConfigError> {
+        let mut list = SubnetList::default();
+        insert!(list. "10.0.0.0/24");
+        insert!(list. "::1/128");
+        list.shrink(true);
+
+        let json = serde_json::to_string(&list).unwrap();
+        let round_trip: SubnetList = serde_json::from_str(&json).unwrap();
+        assert_eq!(collect(&list), collect(&round_trip));
+
+        let subnet = Subnet::parse_subnet("10.0.0.0/24", &Config::default())?.unwrap();
+        assert_eq!(
+            ("10.0.0.0".to_string(), "10.0.0.255".to_string()),
+            subnet.range()
+        );
+        Ok(())
+    }
 }